@@ -1,35 +1,58 @@
-use std::{cell::Cell, cmp::Ordering, collections::HashSet, fs, io, path::Path};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, io,
+    path::PathBuf,
+    process,
+};
 
-use cargo_toml::Manifest;
+use cargo_metadata::{DependencyKind, MetadataCommand, Package};
 use derivative::Derivative;
+use serde::Serialize;
 
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub enum DependencyType {
     Regular,
     Dev,
     Build,
 }
 
-#[derive(Clone, Copy, Debug, Hash, PartialEq, PartialOrd)]
-pub enum State {
-    NotProcessed,
-    Processed,
-}
-
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd)]
 pub struct Dependency {
     /// Name of the crate.
     pub name: String,
     /// Type of dependency -- regular, dev, build.
     pub dep_type: DependencyType,
+    /// Whether this dependency is declared `optional = true`.
+    pub optional: bool,
+    /// Names of the features that activate this dependency, if it is
+    /// optional. Empty for non-optional dependencies.
+    pub activating_features: Vec<String>,
+}
+
+impl Dependency {
+    /// Whether this edge is part of the graph under the given feature
+    /// selection: always true for non-optional dependencies, otherwise true
+    /// only if the selection enables one of `activating_features`.
+    fn is_active(&self, feature_options: &FeatureOptions) -> bool {
+        if !self.optional {
+            return true;
+        }
+
+        feature_options.all_features
+            || self.activating_features.iter().any(|feature| {
+                (feature == "default" && !feature_options.no_default_features)
+                    || feature_options.features.contains(feature)
+            })
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct CrateMetadata {
     /// Name of the crate.
     pub name: String,
-    /// Cargo.toml manifest.
-    pub manifest: Manifest,
+    /// Resolved `cargo_metadata` package, including its dependency list.
+    pub package: Package,
 }
 
 #[derive(Clone, Debug, Derivative)]
@@ -38,8 +61,6 @@ pub struct Node {
     pub name: String,
     #[derivative(Hash = "ignore", PartialEq = "ignore")]
     pub dependencies: HashSet<Dependency>,
-    #[derivative(Hash = "ignore", PartialEq = "ignore")]
-    pub state: Cell<State>,
 }
 
 impl PartialOrd for Node {
@@ -54,56 +75,294 @@ impl Ord for Node {
     }
 }
 
-impl Node {
-    pub fn mark_processed(&self) {
-        self.state.set(State::Processed);
+/// A cycle is a chain of crates that end up in a dependency circle
+#[derive(Debug, Eq)]
+pub struct Cycle(pub Vec<Node>);
+
+impl PartialEq for Cycle {
+    fn eq(&self, other: &Self) -> bool {
+        let mut ours = self.0.clone();
+        let mut theirs = other.0.clone();
+        ours.sort();
+        theirs.sort();
+        ours == theirs
+    }
+}
+
+impl std::hash::Hash for Cycle {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        // Two `Cycle`s are equal regardless of which node they start at
+        // (`PartialEq` above sorts before comparing), so the hash must be
+        // computed over the same sorted order or equal cycles could land in
+        // different `HashSet` buckets and never be deduplicated.
+        let mut nodes = self.0.clone();
+        nodes.sort();
+        nodes.hash(state);
     }
+}
 
-    pub fn is_processed(&self) -> bool {
-        self.state.get() == State::Processed
+impl Cycle {
+    /// Builds the `(from, to, dep_type)` edges that make up this cycle, in
+    /// traversal order, by looking up each node's actual dependency record.
+    ///
+    /// Dev-dependencies never participate in cycle detection (see
+    /// `Tarjan::strong_connect`), so a `Dev`-typed edge is skipped here too:
+    /// if a pair of crates has both a regular/build edge and a dev edge
+    /// between them, the cycle can only have closed via the former.
+    fn edges(&self) -> Vec<(String, String, DependencyType)> {
+        let nodes = &self.0;
+        (0..nodes.len())
+            .filter_map(|i| {
+                let node = &nodes[i];
+                let neighbour = &nodes[(i + 1) % nodes.len()];
+                node.dependencies
+                    .iter()
+                    .find(|dep| dep.name == neighbour.name && dep.dep_type != DependencyType::Dev)
+                    .map(|dep| (node.name.clone(), neighbour.name.clone(), dep.dep_type))
+            })
+            .collect()
+    }
+
+    /// Converts this cycle into a `CyclicDependenciesError` naming the first
+    /// offending edge, for use as a CI gate.
+    pub fn into_error(self) -> Option<CyclicDependenciesError> {
+        let edges = self.edges();
+        let (from, to, _) = edges.first()?.clone();
+        let chain = edges
+            .iter()
+            .map(|(name, _, dep_type)| (name.clone(), *dep_type))
+            .collect();
+
+        Some(CyclicDependenciesError { from, to, chain })
     }
 }
 
-/// A cycle is a chain of crates that end up in a dependency circle
-#[derive(Debug, Hash, Eq)]
-pub struct Cycle(pub Vec<Node>);
+/// Borrowed from rust-analyzer's `CyclicDependenciesError`: names the
+/// specific edge that closes the cycle, plus the full chain of crates and
+/// dependency types that make up the cycle.
+#[derive(Debug)]
+pub struct CyclicDependenciesError {
+    /// Crate at the start of the offending edge.
+    pub from: String,
+    /// Crate the offending edge points to, completing the cycle.
+    pub to: String,
+    /// The full cycle, as `(crate_name, dep_type)` pairs in traversal order.
+    pub chain: Vec<(String, DependencyType)>,
+}
 
-impl PartialEq for Cycle {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.clone().sort() == other.0.clone().sort()
+impl fmt::Display for CyclicDependenciesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "cyclic crate dependency: `{}` depends on `{}`, which cycles back to `{}`",
+            self.from, self.to, self.from
+        )?;
+        let chain = self
+            .chain
+            .iter()
+            .map(|(name, dep_type)| format!("{name} ({dep_type:?})"))
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        write!(f, "  cycle: {chain}")
     }
 }
 
-fn read_crates<P>(dir: P) -> io::Result<Vec<CrateMetadata>>
+impl std::error::Error for CyclicDependenciesError {}
+
+/// How `cargo-workgraph` should run: either emit the dependency graph (as
+/// Graphviz `dot` or JSON), or act as a CI gate that fails when the
+/// workspace contains a cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Emit a Graphviz `digraph` of the cycles found (the historical default).
+    Dot,
+    /// Emit the full resolved graph, plus its cycles, as JSON.
+    Json,
+    /// Exit non-zero with a `CyclicDependenciesError` if any cycle exists.
+    Check,
+}
+
+fn parse_mode() -> Mode {
+    let args = std::env::args().skip(1).collect::<HashSet<String>>();
+
+    if args.contains("--check") {
+        Mode::Check
+    } else if args.contains("--json") {
+        Mode::Json
+    } else {
+        Mode::Dot
+    }
+}
+
+/// Splits a `--features` value on commas and/or spaces, matching the forms
+/// `cargo` itself accepts (e.g. `a,b`, `a b`, `"a b"`).
+fn split_features(value: &str) -> Vec<String> {
+    value
+        .split([',', ' '])
+        .map(str::trim)
+        .filter(|feature| !feature.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Parses `--all-features`, `--no-default-features` and `--features` from
+/// the command line, mirroring the equivalent `cargo` flags. `--features`
+/// is accepted both as `--features=a,b,c` and as the more common
+/// space-separated `--features a,b,c`.
+fn parse_feature_options_from<I>(args: I) -> FeatureOptions
 where
-    P: AsRef<Path>,
+    I: IntoIterator<Item = String>,
 {
-    let dir = dir.as_ref();
-    let crate_metadatas = fs::read_dir(dir)?
-        .filter_map(Result::ok)
-        .filter_map(|entry| {
-            let crate_dir = entry.path();
-            let manifest_path = crate_dir.join("Cargo.toml");
-            if manifest_path.exists() {
-                Manifest::from_path(&manifest_path)
-                    .ok()
-                    .map(|manifest| (manifest_path, manifest))
-            } else {
-                None
+    let mut all_features = false;
+    let mut no_default_features = false;
+    let mut features = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--all-features" {
+            all_features = true;
+        } else if arg == "--no-default-features" {
+            no_default_features = true;
+        } else if let Some(value) = arg.strip_prefix("--features=") {
+            features.extend(split_features(value));
+        } else if arg == "--features" {
+            if let Some(value) = args.next() {
+                features.extend(split_features(&value));
             }
-        })
-        .map(|(manifest_path, manifest)| {
-            let name = manifest
-                .package
-                .as_ref()
-                .map(|package| package.name.clone())
-                .unwrap_or_else(|| {
-                    panic!(
-                        "[package] section missing for manifest: {}",
-                        manifest_path.display()
-                    )
-                });
-            CrateMetadata { name, manifest }
+        }
+    }
+
+    FeatureOptions {
+        all_features,
+        no_default_features,
+        features,
+    }
+}
+
+fn parse_feature_options() -> FeatureOptions {
+    parse_feature_options_from(std::env::args().skip(1))
+}
+
+/// Parses `--frozen`, `--locked`, `--offline`, `--manifest-path` and
+/// `--current-dir` from the command line, mirroring the equivalent `cargo`
+/// flags so the tool can run deterministically in CI.
+fn parse_workspace_load_options_from<I>(args: I) -> WorkspaceLoadOptions
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut options = WorkspaceLoadOptions::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--frozen" {
+            options.frozen = true;
+        } else if arg == "--locked" {
+            options.locked = true;
+        } else if arg == "--offline" {
+            options.offline = true;
+        } else if let Some(value) = arg.strip_prefix("--manifest-path=") {
+            options.manifest_path = Some(PathBuf::from(value));
+        } else if arg == "--manifest-path" {
+            if let Some(value) = args.next() {
+                options.manifest_path = Some(PathBuf::from(value));
+            }
+        } else if let Some(value) = arg.strip_prefix("--current-dir=") {
+            options.current_dir = Some(PathBuf::from(value));
+        } else if arg == "--current-dir" {
+            if let Some(value) = args.next() {
+                options.current_dir = Some(PathBuf::from(value));
+            }
+        }
+    }
+
+    options
+}
+
+fn parse_workspace_load_options() -> WorkspaceLoadOptions {
+    parse_workspace_load_options_from(std::env::args().skip(1))
+}
+
+/// Controls how the workspace graph is resolved, mirroring the `cargo`
+/// flags that make the resolution deterministic in CI.
+#[derive(Clone, Debug, Default)]
+pub struct WorkspaceLoadOptions {
+    /// Directory to invoke `cargo metadata` from, defaults to the current directory.
+    pub current_dir: Option<PathBuf>,
+    /// Path to the workspace (or crate) `Cargo.toml` to inspect.
+    pub manifest_path: Option<PathBuf>,
+    /// Equivalent to `cargo --frozen`: require `Cargo.lock` and the registry cache
+    /// to be up to date, without touching the network.
+    pub frozen: bool,
+    /// Equivalent to `cargo --locked`: require `Cargo.lock` to be up to date.
+    pub locked: bool,
+    /// Equivalent to `cargo --offline`: do not access the network at all.
+    pub offline: bool,
+}
+
+/// Controls which optional, feature-gated dependencies count as active edges
+/// when resolving cycles, analogous to `krates`' `all_features` /
+/// `no_default_features` / explicit `features` knobs.
+#[derive(Clone, Debug, Default)]
+pub struct FeatureOptions {
+    /// Equivalent to `cargo --all-features`: every optional dependency is active.
+    pub all_features: bool,
+    /// Equivalent to `cargo --no-default-features`: the `default` feature is not active.
+    pub no_default_features: bool,
+    /// Equivalent to `cargo --features <features>`: these features are active,
+    /// in addition to `default` unless `no_default_features` is set.
+    pub features: Vec<String>,
+}
+
+/// Runs `cargo metadata` against the workspace and returns its resolved
+/// packages as `CrateMetadata`.
+///
+/// This replaces the previous best-effort directory walk: `cargo_metadata`
+/// understands `[workspace]` glob members, nested members, and path/registry
+/// dependencies, so the resulting graph matches what `cargo build` itself
+/// would resolve.
+///
+/// Passes `--no-deps`: this tool only ever reads `package.dependencies` (the
+/// manifest-level dependency list, identical with or without full
+/// resolution), and full resolution makes `cargo metadata` itself refuse a
+/// workspace with a real dependency cycle -- exactly the topology this tool
+/// exists to detect.
+fn read_crates(options: &WorkspaceLoadOptions) -> io::Result<Vec<CrateMetadata>> {
+    let mut cmd = MetadataCommand::new();
+    cmd.no_deps();
+
+    if let Some(manifest_path) = options.manifest_path.as_ref() {
+        cmd.manifest_path(manifest_path);
+    }
+    if let Some(current_dir) = options.current_dir.as_ref() {
+        cmd.current_dir(current_dir);
+    }
+
+    let mut other_options = Vec::new();
+    if options.frozen {
+        other_options.push(String::from("--frozen"));
+    }
+    if options.locked {
+        other_options.push(String::from("--locked"));
+    }
+    if options.offline {
+        other_options.push(String::from("--offline"));
+    }
+    if !other_options.is_empty() {
+        cmd.other_options(other_options);
+    }
+
+    let metadata = cmd
+        .exec()
+        .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+
+    let workspace_members = metadata.workspace_members;
+    let crate_metadatas = metadata
+        .packages
+        .into_iter()
+        .filter(|package| workspace_members.contains(&package.id))
+        .map(|package| CrateMetadata {
+            name: package.name.clone(),
+            package,
         })
         .collect();
 
@@ -119,92 +378,212 @@ fn build_nodes(all_crates: Vec<CrateMetadata>) -> Vec<Node> {
     all_crates
         .into_iter()
         .map(|crate_metadata| {
-            let CrateMetadata { name, manifest } = crate_metadata;
-
-            let dependencies_regular = manifest.dependencies;
-            let dependencies_regular =
-                dependencies_regular
-                    .into_iter()
-                    .map(|(name, _)| Dependency {
-                        name,
-                        dep_type: DependencyType::Regular,
-                    });
-
-            let dependencies_dev = manifest.dev_dependencies;
-            let dependencies_dev = dependencies_dev.into_iter().map(|(name, _)| Dependency {
-                name,
-                dep_type: DependencyType::Dev,
-            });
+            let CrateMetadata { name, package } = crate_metadata;
+            let features = package.features.clone();
 
-            let dependencies = dependencies_regular
-                .chain(dependencies_dev)
+            let dependencies = package
+                .dependencies
+                .into_iter()
+                .filter_map(|dependency| {
+                    let dep_type = match dependency.kind {
+                        DependencyKind::Normal => DependencyType::Regular,
+                        DependencyKind::Development => DependencyType::Dev,
+                        DependencyKind::Build => DependencyType::Build,
+                        DependencyKind::Unknown => return None,
+                    };
+
+                    let activating_features = if dependency.optional {
+                        activating_features(&features, &dependency.name)
+                    } else {
+                        Vec::new()
+                    };
+
+                    Some(Dependency {
+                        name: dependency.name,
+                        dep_type,
+                        optional: dependency.optional,
+                        activating_features,
+                    })
+                })
                 .filter(|dep| crate_names.iter().any(|name| name == &dep.name))
                 .collect::<HashSet<Dependency>>();
 
-            Node {
-                name,
-                dependencies,
-                state: Cell::new(State::NotProcessed),
-            }
+            Node { name, dependencies }
         })
         .collect::<Vec<Node>>()
 }
 
-fn detect_cycles_all<'l>(nodes: &'l Vec<Node>) -> HashSet<Cycle> {
-    // Clone while no nodes are marked processed.
-    nodes
+/// Finds the names of the features that activate `dep_name`, by scanning the
+/// crate's `[features]` table for the implicit (`dep_name`) and explicit
+/// (`dep:dep_name`, `dep_name?/feature`) activation syntaxes `cargo` supports,
+/// then closes the result transitively over plain feature-to-feature
+/// requirements (e.g. `x = ["y"]` where `y` itself activates `dep_name`), so
+/// selecting `x` is recognised as activating `dep_name` too.
+fn activating_features(features: &BTreeMap<String, Vec<String>>, dep_name: &str) -> Vec<String> {
+    let explicit = format!("dep:{dep_name}");
+    let weak_prefix = format!("{dep_name}?/");
+    let strong_prefix = format!("{dep_name}/");
+
+    let mut activating: HashSet<String> = features
         .iter()
-        .flat_map(|node| detect_cycle(&(nodes.clone()), &mut Vec::new(), node))
-        .collect::<HashSet<Cycle>>()
+        .filter(|(_, requirements)| {
+            requirements.iter().any(|requirement| {
+                requirement == dep_name
+                    || requirement == &explicit
+                    || requirement.starts_with(&weak_prefix)
+                    || requirement.starts_with(&strong_prefix)
+            })
+        })
+        .map(|(feature, _)| feature.clone())
+        .collect();
+
+    loop {
+        let mut grew = false;
+        for (feature, requirements) in features {
+            if activating.contains(feature) {
+                continue;
+            }
+            if requirements
+                .iter()
+                .any(|requirement| activating.contains(requirement))
+            {
+                activating.insert(feature.clone());
+                grew = true;
+            }
+        }
+        if !grew {
+            break;
+        }
+    }
+
+    let mut activating = activating.into_iter().collect::<Vec<_>>();
+    activating.sort();
+    activating
 }
 
-fn detect_cycle<'n>(nodes: &'n [Node], node_buffer: &mut Vec<Node>, node: &'n Node) -> Vec<Cycle> {
-    if node.is_processed() && node_buffer.contains(node) {
-        // Found a cycle
-        // let node_cycle_start = node;
+/// Runs Tarjan's strongly-connected-components algorithm over the node
+/// dependency edges, tracking `index`/`lowlink` per node and an explicit
+/// `on_stack` set -- see Tarjan (1972).
+struct Tarjan<'n> {
+    nodes: &'n [Node],
+    name_to_index: HashMap<&'n str, usize>,
+    feature_options: &'n FeatureOptions,
+    counter: usize,
+    index: Vec<Option<usize>>,
+    lowlink: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+    sccs: Vec<Vec<usize>>,
+}
 
-        // Delete all the nodes in the cycle buffer before cycle_start.
-        let cycle = Cycle(
-            node_buffer
-                .drain(..)
-                // .skip_while(|node| node != node_cycle_start)
-                .collect::<Vec<Node>>(),
-        );
+impl<'n> Tarjan<'n> {
+    fn new(nodes: &'n [Node], feature_options: &'n FeatureOptions) -> Self {
+        let name_to_index = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (node.name.as_str(), index))
+            .collect();
 
-        vec![cycle]
-    } else {
-        node.mark_processed();
+        Tarjan {
+            nodes,
+            name_to_index,
+            feature_options,
+            counter: 0,
+            index: vec![None; nodes.len()],
+            lowlink: vec![0; nodes.len()],
+            on_stack: vec![false; nodes.len()],
+            stack: Vec::new(),
+            sccs: Vec::new(),
+        }
+    }
 
-        let filter: fn(&&Dependency) -> bool = if node_buffer.is_empty() {
-            |_dep: &&Dependency| true
-        } else {
-            |dep: &&Dependency| dep.dep_type != DependencyType::Dev
-        };
+    fn run(mut self) -> Vec<Vec<usize>> {
+        for v in 0..self.nodes.len() {
+            if self.index[v].is_none() {
+                self.strong_connect(v);
+            }
+        }
 
-        node_buffer.push(node.clone());
+        self.sccs
+    }
 
-        // Detect the first one.
-        node.dependencies
-            .iter()
-            .filter(filter)
-            .fold(Vec::new(), |mut cycles, dep| {
-                let dep_node = nodes
-                    .iter()
-                    .find(|node| &node.name == &dep.name)
-                    .unwrap_or_else(|| {
-                        panic!(
-                            "Expected `{}` to have dependency on: `{}`",
-                            &node.name, &dep.name
-                        )
-                    });
-
-                cycles.extend(detect_cycle(nodes, &mut (node_buffer.clone()), dep_node));
-                cycles
-            })
+    fn strong_connect(&mut self, v: usize) {
+        self.index[v] = Some(self.counter);
+        self.lowlink[v] = self.counter;
+        self.counter += 1;
+
+        self.stack.push(v);
+        self.on_stack[v] = true;
+
+        for dep in &self.nodes[v].dependencies {
+            // A dev-dependency only needs the other crate's library target,
+            // never its own test target, so nothing else ever depends on
+            // the edge it introduces -- it can't close a real build cycle
+            // (e.g. crate `a` regularly depends on `b`, and `b` dev-depends
+            // on `a` for integration tests; `cargo build`/`cargo test` both
+            // succeed for that topology). Exclude it from the graph we walk.
+            if dep.dep_type == DependencyType::Dev {
+                continue;
+            }
+
+            if !dep.is_active(self.feature_options) {
+                continue;
+            }
+
+            let Some(&w) = self.name_to_index.get(dep.name.as_str()) else {
+                continue;
+            };
+
+            if self.index[w].is_none() {
+                self.strong_connect(w);
+                self.lowlink[v] = self.lowlink[v].min(self.lowlink[w]);
+            } else if self.on_stack[w] {
+                self.lowlink[v] = self.lowlink[v].min(self.index[w].expect("already visited"));
+            }
+        }
+
+        if self.lowlink[v] == self.index[v].expect("just set above") {
+            let mut scc = Vec::new();
+            loop {
+                let w = self
+                    .stack
+                    .pop()
+                    .expect("node on the Tarjan stack below `v` must exist");
+                self.on_stack[w] = false;
+                scc.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            self.sccs.push(scc);
+        }
     }
 }
 
-fn print_cycles(cycles: HashSet<Cycle>) {
+fn detect_cycles_all(nodes: &[Node], feature_options: &FeatureOptions) -> HashSet<Cycle> {
+    Tarjan::new(nodes, feature_options)
+        .run()
+        .into_iter()
+        .filter(|scc| {
+            scc.len() >= 2
+                || scc.iter().any(|&v| {
+                    nodes[v].dependencies.iter().any(|dep| {
+                        dep.name == nodes[v].name
+                            && dep.dep_type != DependencyType::Dev
+                            && dep.is_active(feature_options)
+                    })
+                })
+        })
+        // Tarjan pops an SCC in reverse push order; reverse it back so each
+        // node is followed by one it depends on, as `Cycle::edges` expects.
+        .map(|scc| Cycle(scc.into_iter().rev().map(|v| nodes[v].clone()).collect()))
+        .collect()
+}
+
+/// Emits the cycles as a Graphviz `digraph`, the historical (and default)
+/// output of this tool. Only cycles of two or more crates are drawn; a
+/// crate that depends on itself is not shown.
+fn emit_dot(cycles: HashSet<Cycle>) {
     let node_style = r##"
         node [
             fillcolor = "#bbddff",
@@ -258,21 +637,17 @@ fn print_cycles(cycles: HashSet<Cycle>) {
                 // });
 
                 if let Some(dep) = dep.as_ref() {
-                    if dep.dep_type == DependencyType::Dev {
-                        println!(
-                            "{krate}{index} -> {dep}{index} [label = <<b>  DEV</b>>];",
-                            krate = &node.name,
-                            dep = &dep.name,
-                            index = index
-                        );
-                    } else {
-                        println!(
-                            "{krate}{index} -> {dep}{index} [label = <<b>  REG</b>>];",
-                            krate = &node.name,
-                            dep = &dep.name,
-                            index = index
-                        );
-                    }
+                    // Same `{dep_type:?}` formatting as `CyclicDependenciesError`'s
+                    // `Display` impl, so `Build` edges get their own label
+                    // instead of being folded into "REG" like before this
+                    // series modelled them.
+                    println!(
+                        "{krate}{index} -> {dep}{index} [label = <<b>  {dep_type:?}</b>>];",
+                        krate = &node.name,
+                        dep = &dep.name,
+                        index = index,
+                        dep_type = dep.dep_type
+                    );
                 } else {
                     // println!(
                     //     "{krate}{index} -> {dep}{index};",
@@ -292,14 +667,409 @@ fn print_cycles(cycles: HashSet<Cycle>) {
     println!("}}");
 }
 
+/// One crate's entry in the JSON graph: its name and its typed dependency
+/// edges.
+#[derive(Serialize)]
+struct CrateJson {
+    name: String,
+    dependencies: Vec<EdgeJson>,
+}
+
+/// One dependency edge in the JSON graph.
+#[derive(Serialize, PartialEq, Eq, PartialOrd, Ord)]
+struct EdgeJson {
+    name: String,
+    dep_type: DependencyType,
+}
+
+/// One cycle in the JSON graph, as its chain of edges in traversal order.
+/// Unlike in `CrateJson::dependencies`, each entry's `name` is the crate the
+/// edge starts from, and `dep_type` is the type of its edge to the next crate.
+#[derive(Serialize, PartialEq, Eq, PartialOrd, Ord)]
+struct CycleJson {
+    chain: Vec<EdgeJson>,
+}
+
+/// The full resolved workspace graph: every crate with its dependency edges,
+/// plus the cycles found within it. This is a build-system-agnostic crate
+/// graph description, analogous to rust-analyzer's `project-lock.json`.
+#[derive(Serialize)]
+struct WorkspaceGraphJson {
+    crates: Vec<CrateJson>,
+    cycles: Vec<CycleJson>,
+}
+
+/// Builds the JSON graph from the resolved nodes and cycles. The `cycles`
+/// array is sorted before returning: it's built from a `HashSet<Cycle>`,
+/// whose iteration order varies from run to run, and the whole point of
+/// this emitter is a diffable, reproducible document.
+fn build_graph_json(nodes: &[Node], cycles: HashSet<Cycle>) -> WorkspaceGraphJson {
+    let crates = nodes
+        .iter()
+        .map(|node| {
+            let mut dependencies = node
+                .dependencies
+                .iter()
+                .map(|dep| EdgeJson {
+                    name: dep.name.clone(),
+                    dep_type: dep.dep_type,
+                })
+                .collect::<Vec<_>>();
+            dependencies.sort();
+
+            CrateJson {
+                name: node.name.clone(),
+                dependencies,
+            }
+        })
+        .collect();
+
+    let mut cycles = cycles
+        .into_iter()
+        .map(|cycle| {
+            let chain = cycle
+                .edges()
+                .into_iter()
+                .map(|(name, _to, dep_type)| EdgeJson { name, dep_type })
+                .collect();
+
+            CycleJson { chain }
+        })
+        .collect::<Vec<_>>();
+    cycles.sort();
+
+    WorkspaceGraphJson { crates, cycles }
+}
+
+/// Emits every node and its dependency edges, plus the cycles found, as a
+/// single machine-readable JSON document.
+fn emit_json(nodes: &[Node], cycles: HashSet<Cycle>) -> serde_json::Result<()> {
+    let graph = build_graph_json(nodes, cycles);
+
+    println!("{}", serde_json::to_string_pretty(&graph)?);
+
+    Ok(())
+}
+
 fn main() -> io::Result<()> {
-    let mut all_crates = read_crates("app")?;
-    all_crates.extend(read_crates("crate")?);
+    let options = parse_workspace_load_options();
+    let feature_options = parse_feature_options();
+    let all_crates = read_crates(&options)?;
 
     let nodes = build_nodes(all_crates);
-    let cycles = detect_cycles_all(&nodes);
+    let cycles = detect_cycles_all(&nodes, &feature_options);
 
-    print_cycles(cycles);
+    // Note how many of the cycles found only appear under the requested
+    // feature set, i.e. are absent under the default feature set -- e.g. a
+    // cycle through an optional dependency that `--all-features` would hit
+    // but a default build would not. Dev-dependency edges are never part of
+    // a cycle, under any feature set.
+    if feature_options.all_features
+        || feature_options.no_default_features
+        || !feature_options.features.is_empty()
+    {
+        let unconditional_cycles = detect_cycles_all(&nodes, &FeatureOptions::default());
+        let feature_only_count = cycles.difference(&unconditional_cycles).count();
+        if feature_only_count > 0 {
+            eprintln!(
+                "note: {feature_only_count} cycle(s) only appear under the selected feature set"
+            );
+        }
+    }
+
+    match parse_mode() {
+        Mode::Check => {
+            if let Some(error) = cycles.into_iter().find_map(Cycle::into_error) {
+                eprintln!("error: {error}");
+                process::exit(1);
+            }
+        }
+        Mode::Dot => emit_dot(cycles),
+        Mode::Json => emit_json(&nodes, cycles)
+            .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?,
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dep(name: &str, dep_type: DependencyType) -> Dependency {
+        Dependency {
+            name: name.to_string(),
+            dep_type,
+            optional: false,
+            activating_features: Vec::new(),
+        }
+    }
+
+    fn node(name: &str, dependencies: Vec<Dependency>) -> Node {
+        Node {
+            name: name.to_string(),
+            dependencies: dependencies.into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn dev_dependency_back_edge_is_not_a_cycle() {
+        // `a` regularly depends on `b`; `b` dev-depends on `a` for
+        // integration tests -- `cargo build`/`cargo test` succeed for this
+        // topology, so it must not be reported as a cycle.
+        let nodes = vec![
+            node("a", vec![dep("b", DependencyType::Regular)]),
+            node("b", vec![dep("a", DependencyType::Dev)]),
+        ];
+
+        let cycles = detect_cycles_all(&nodes, &FeatureOptions::default());
+
+        assert!(cycles.is_empty());
+    }
+
+    #[test]
+    fn regular_dependency_cycle_is_still_detected() {
+        let nodes = vec![
+            node("a", vec![dep("b", DependencyType::Regular)]),
+            node("b", vec![dep("a", DependencyType::Regular)]),
+        ];
+
+        let cycles = detect_cycles_all(&nodes, &FeatureOptions::default());
+
+        assert_eq!(cycles.len(), 1);
+    }
+
+    #[test]
+    fn check_mode_does_not_error_on_dev_dependency_back_edge() {
+        // Same topology as `dev_dependency_back_edge_is_not_a_cycle`, but
+        // exercised through the `--check` code path: it must not turn into
+        // a `CyclicDependenciesError`, or the CI gate would fail builds that
+        // `cargo build`/`cargo test` themselves accept.
+        let nodes = vec![
+            node("a", vec![dep("b", DependencyType::Regular)]),
+            node("b", vec![dep("a", DependencyType::Dev)]),
+        ];
+
+        let cycles = detect_cycles_all(&nodes, &FeatureOptions::default());
+        let error = cycles.into_iter().find_map(Cycle::into_error);
+
+        assert!(error.is_none());
+    }
+
+    #[test]
+    fn parse_workspace_load_options_reads_lock_and_network_flags() {
+        let options = parse_workspace_load_options_from(
+            ["--frozen", "--locked", "--offline"]
+                .into_iter()
+                .map(String::from),
+        );
+
+        assert!(options.frozen);
+        assert!(options.locked);
+        assert!(options.offline);
+    }
+
+    #[test]
+    fn parse_workspace_load_options_reads_manifest_path_and_current_dir() {
+        let options = parse_workspace_load_options_from(
+            [
+                "--manifest-path",
+                "other/Cargo.toml",
+                "--current-dir=/workspace",
+            ]
+            .into_iter()
+            .map(String::from),
+        );
+
+        assert_eq!(
+            options.manifest_path,
+            Some(PathBuf::from("other/Cargo.toml"))
+        );
+        assert_eq!(options.current_dir, Some(PathBuf::from("/workspace")));
+    }
+
+    #[test]
+    fn json_cycles_are_sorted_regardless_of_hashset_iteration_order() {
+        let cycle_ab = Cycle(vec![
+            node("a", vec![dep("b", DependencyType::Regular)]),
+            node("b", vec![dep("a", DependencyType::Regular)]),
+        ]);
+        let cycle_cd = Cycle(vec![
+            node("c", vec![dep("d", DependencyType::Regular)]),
+            node("d", vec![dep("c", DependencyType::Regular)]),
+        ]);
+
+        let mut first = HashSet::new();
+        first.insert(cycle_ab);
+        first.insert(cycle_cd);
+
+        let cycle_ab = Cycle(vec![
+            node("a", vec![dep("b", DependencyType::Regular)]),
+            node("b", vec![dep("a", DependencyType::Regular)]),
+        ]);
+        let cycle_cd = Cycle(vec![
+            node("c", vec![dep("d", DependencyType::Regular)]),
+            node("d", vec![dep("c", DependencyType::Regular)]),
+        ]);
+
+        let mut second = HashSet::new();
+        second.insert(cycle_cd);
+        second.insert(cycle_ab);
+
+        let first_json = serde_json::to_string(&build_graph_json(&[], first)).unwrap();
+        let second_json = serde_json::to_string(&build_graph_json(&[], second)).unwrap();
+
+        assert_eq!(first_json, second_json);
+    }
+
+    #[test]
+    fn parse_feature_options_supports_equals_and_space_separated_features() {
+        let equals =
+            parse_feature_options_from(["--features=a,b"].into_iter().map(String::from));
+        let spaced =
+            parse_feature_options_from(["--features", "a,b"].into_iter().map(String::from));
+
+        assert_eq!(equals.features, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(spaced.features, vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn parse_feature_options_reads_all_features_and_no_default_features() {
+        let options = parse_feature_options_from(
+            ["--all-features", "--no-default-features"]
+                .into_iter()
+                .map(String::from),
+        );
+
+        assert!(options.all_features);
+        assert!(options.no_default_features);
+    }
+
+    #[test]
+    fn optional_dependency_is_active_only_when_its_feature_is_selected() {
+        let optional_dep = Dependency {
+            activating_features: vec!["foo-support".to_string()],
+            ..dep("foo", DependencyType::Regular)
+        };
+        let optional_dep = Dependency {
+            optional: true,
+            ..optional_dep
+        };
+
+        assert!(!optional_dep.is_active(&FeatureOptions::default()));
+
+        let with_feature = FeatureOptions {
+            features: vec!["foo-support".to_string()],
+            ..FeatureOptions::default()
+        };
+        assert!(optional_dep.is_active(&with_feature));
+
+        let with_all_features = FeatureOptions {
+            all_features: true,
+            ..FeatureOptions::default()
+        };
+        assert!(optional_dep.is_active(&with_all_features));
+    }
+
+    #[test]
+    fn activating_features_includes_features_that_transitively_enable_a_dependency() {
+        // x = ["y"]; y = ["dep:z"] -- selecting `x` should be recognised as
+        // activating `z`, not just `y`.
+        let features = BTreeMap::from([
+            ("x".to_string(), vec!["y".to_string()]),
+            ("y".to_string(), vec!["dep:z".to_string()]),
+        ]);
+
+        assert_eq!(
+            activating_features(&features, "z"),
+            vec!["x".to_string(), "y".to_string()],
+        );
+
+        let z_dep = Dependency {
+            optional: true,
+            activating_features: activating_features(&features, "z"),
+            ..dep("z", DependencyType::Regular)
+        };
+
+        let with_x = FeatureOptions {
+            features: vec!["x".to_string()],
+            ..FeatureOptions::default()
+        };
+        assert!(z_dep.is_active(&with_x));
+    }
+
+    #[test]
+    fn check_mode_errors_on_regular_dependency_cycle() {
+        let nodes = vec![
+            node("a", vec![dep("b", DependencyType::Regular)]),
+            node("b", vec![dep("a", DependencyType::Regular)]),
+        ];
+
+        let cycles = detect_cycles_all(&nodes, &FeatureOptions::default());
+        let error = cycles
+            .into_iter()
+            .find_map(Cycle::into_error)
+            .expect("a regular dependency cycle must be reported");
+
+        assert_eq!(error.chain.len(), 2);
+    }
+
+    /// Writes a two-crate workspace to a fresh temp directory, where `a`
+    /// regular-depends on `b` (a path dependency) and `b` regular-depends
+    /// back on `a` -- the textbook cycle this tool exists to catch, and the
+    /// exact shape `cargo metadata` itself refuses to resolve unless called
+    /// with `--no-deps`.
+    fn write_cyclic_workspace_fixture() -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("cargo_workgraph_cyclic_fixture_{}", process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("a/src")).unwrap();
+        std::fs::create_dir_all(dir.join("b/src")).unwrap();
+
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"a\", \"b\"]\nresolver = \"2\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("a/Cargo.toml"),
+            "[package]\nname = \"a\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [dependencies]\nb = { path = \"../b\" }\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("a/src/lib.rs"), "").unwrap();
+        std::fs::write(
+            dir.join("b/Cargo.toml"),
+            "[package]\nname = \"b\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [dependencies]\na = { path = \"../a\" }\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("b/src/lib.rs"), "").unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn read_crates_resolves_a_workspace_with_a_real_dependency_cycle() {
+        let dir = write_cyclic_workspace_fixture();
+        let options = WorkspaceLoadOptions {
+            current_dir: Some(dir.clone()),
+            ..WorkspaceLoadOptions::default()
+        };
+
+        let result = read_crates(&options);
+        std::fs::remove_dir_all(&dir).ok();
+
+        let all_crates = result.expect(
+            "cargo metadata --no-deps must resolve a workspace with a real \
+             dependency cycle, not error out before Tarjan ever runs",
+        );
+        let mut names = all_crates
+            .iter()
+            .map(|crate_metadata| crate_metadata.name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+
+        assert_eq!(names, vec!["a".to_string(), "b".to_string()]);
+    }
+}